@@ -5,6 +5,7 @@
 extern crate backtrace;
 
 use backtrace::Backtrace;
+use std::any::type_name;
 use std::cell::{
     BorrowError, BorrowMutError, Ref as StdRef, RefCell as StdRefCell, RefMut as StdRefMut,
 };
@@ -12,6 +13,11 @@ use std::fmt::{Debug, Display, Error, Formatter};
 use std::ops::{Deref, DerefMut};
 use std::{env, mem};
 
+mod trust_cell;
+
+pub use trust_cell::{AccountableTrustCell, InvalidBorrow};
+pub use trust_cell::{Ref as TrustCellRef, RefMut as TrustCellRefMut};
+
 /// A RefCell that tracks outstanding borrows and reports stack traces for dynamic borrow failures.
 #[derive(Debug)]
 pub struct RefCell<T: ?Sized> {
@@ -28,15 +34,26 @@ struct BorrowData {
 #[derive(Debug)]
 struct BorrowRecord {
     id: usize,
-    backtrace: Backtrace,
+    // `None` when `RUST_BACKTRACE` wasn't set at the time this borrow was taken, so that the
+    // common case of backtraces being disabled doesn't even pay for capturing instruction
+    // pointers.
+    backtrace: Option<Backtrace>,
 }
 
 impl BorrowData {
     fn record(&mut self) -> usize {
         let id = self.next_id();
+        // Only the instruction pointers are captured here; resolving symbols is comparatively
+        // expensive and is deferred until a panic actually needs to print a backtrace. When
+        // backtraces aren't even requested, skip the capture entirely.
+        let backtrace = if backtraces_requested() {
+            Some(Backtrace::new_unresolved())
+        } else {
+            None
+        };
         self.borrows.push(BorrowRecord {
             id: id,
-            backtrace: Backtrace::new(),
+            backtrace: backtrace,
         });
         id
     }
@@ -100,6 +117,30 @@ impl<'a, T: ?Sized> Ref<'a, T> {
             data: orig.data,
         }
     }
+
+    /// Split one borrow into two disjoint borrows of different parts of the borrowed value.
+    ///
+    /// Each returned `Ref` carries its own borrow record, so dropping one does not clear the
+    /// accounting for the other.
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(orig: Ref<'a, T>, f: F) -> (Ref<'a, U>, Ref<'a, V>)
+    where
+        F: FnOnce(&T) -> (&U, &V),
+    {
+        let Ref { inner, data } = orig;
+        let cell = data.cell;
+        let (a, b) = StdRef::map_split(inner, f);
+        let second_id = cell.borrow_mut().record();
+        (
+            Ref { inner: a, data },
+            Ref {
+                inner: b,
+                data: RefBorrowData {
+                    cell,
+                    id: second_id,
+                },
+            },
+        )
+    }
 }
 
 impl<'a, T: ?Sized + Display> Display for Ref<'a, T> {
@@ -150,6 +191,34 @@ impl<'a, T: ?Sized> RefMut<'a, T> {
             data,
         }
     }
+
+    /// Split one mutable borrow into two disjoint mutable borrows of different parts of the
+    /// borrowed value.
+    ///
+    /// Each returned `RefMut` carries its own borrow record, so dropping one does not clear the
+    /// accounting for the other.
+    pub fn map_split<U: ?Sized, V: ?Sized, F>(
+        orig: RefMut<'a, T>,
+        f: F,
+    ) -> (RefMut<'a, U>, RefMut<'a, V>)
+    where
+        F: FnOnce(&mut T) -> (&mut U, &mut V),
+    {
+        let RefMut { inner, data } = orig;
+        let cell = data.cell;
+        let (a, b) = StdRefMut::map_split(inner, f);
+        let second_id = cell.borrow_mut().record();
+        (
+            RefMut { inner: a, data },
+            RefMut {
+                inner: b,
+                data: RefBorrowData {
+                    cell,
+                    id: second_id,
+                },
+            },
+        )
+    }
 }
 
 impl<'a, T: ?Sized> Deref for RefMut<'a, T> {
@@ -180,13 +249,13 @@ impl<T: ?Sized> RefCell<T> {
                 },
             }
         } else {
-            if let Ok(var) = env::var("RUST_BACKTRACE") {
-                if !var.is_empty() {
-                    eprintln!("Outstanding borrow:");
-                    print_filtered_backtrace(&self.borrows.borrow().borrows[0].backtrace);
+            if backtraces_requested() {
+                eprintln!("Outstanding borrow of RefCell<{}>:", type_name::<T>());
+                if let Some(backtrace) = &mut self.borrows.borrow_mut().borrows[0].backtrace {
+                    print_filtered_backtrace(backtrace);
                 }
             }
-            panic!("RefCell is already mutably borrowed.");
+            panic!("RefCell<{}> is already mutably borrowed.", type_name::<T>());
         }
     }
 
@@ -216,16 +285,16 @@ impl<T: ?Sized> RefCell<T> {
                 },
             }
         } else {
-            if let Ok(var) = env::var("RUST_BACKTRACE") {
-                if !var.is_empty() {
-                    eprintln!("Outstanding borrows:");
-                    for borrow in &*self.borrows.borrow().borrows {
-                        print_filtered_backtrace(&borrow.backtrace);
-                        eprintln!("");
+            if backtraces_requested() {
+                eprintln!("Outstanding borrow(s) of RefCell<{}>:", type_name::<T>());
+                for borrow in &mut *self.borrows.borrow_mut().borrows {
+                    if let Some(backtrace) = &mut borrow.backtrace {
+                        print_filtered_backtrace(backtrace);
                     }
+                    eprintln!("");
                 }
             }
-            panic!("RefCell is already borrowed.");
+            panic!("RefCell<{}> is already borrowed.", type_name::<T>());
         }
     }
 
@@ -249,6 +318,66 @@ impl<T: ?Sized> RefCell<T> {
     pub unsafe fn try_borrow_unguarded(&self) -> Result<&T, BorrowError> {
         self.inner.try_borrow_unguarded()
     }
+
+    /// Query the current borrow state of this cell without taking out a borrow of its own.
+    ///
+    /// This lets callers branch on whether `borrow()`/`borrow_mut()` would succeed before
+    /// calling them, without risking a panic.
+    pub fn borrow_state(&self) -> BorrowState {
+        if self.inner.try_borrow_mut().is_ok() {
+            BorrowState::Unused
+        } else if self.inner.try_borrow().is_ok() {
+            BorrowState::Reading
+        } else {
+            BorrowState::Writing
+        }
+    }
+
+    /// Returns a snapshot of every borrow currently outstanding on this cell, without panicking
+    /// or taking out a borrow of its own.
+    ///
+    /// Each report carries the id of the borrow and a resolved, filtered backtrace of where it
+    /// was taken, the same information that would otherwise only be dumped to stderr from the
+    /// panic path of `borrow`/`borrow_mut`. This is useful for building custom leak detectors or
+    /// deadlock diagnostics, e.g. periodically asserting that no cell has held a borrow across an
+    /// `await` point or event-loop turn.
+    pub fn outstanding_borrows(&self) -> Vec<BorrowReport> {
+        self.borrows
+            .borrow_mut()
+            .borrows
+            .iter_mut()
+            .map(|record| BorrowReport {
+                id: record.id,
+                frames: record
+                    .backtrace
+                    .as_mut()
+                    .map(filtered_backtrace_frames)
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
+
+/// A single outstanding borrow, as returned by `RefCell::outstanding_borrows`.
+#[derive(Clone, Debug)]
+pub struct BorrowReport {
+    /// The id of the borrow, matching the order in which borrows of this cell were taken.
+    pub id: usize,
+    /// The resolved, filtered stack frames of where the borrow was taken. Empty if
+    /// `RUST_BACKTRACE` wasn't set when the borrow was taken.
+    pub frames: Vec<String>,
+}
+
+/// Describes the current borrow state of a `RefCell`, as returned by `RefCell::borrow_state`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BorrowState {
+    /// The cell is not currently borrowed, so either `borrow()` or `borrow_mut()` would succeed.
+    Unused,
+    /// The cell is currently borrowed immutably; further immutable borrows would succeed, but a
+    /// mutable borrow would panic.
+    Reading,
+    /// The cell is currently borrowed mutably; any further borrow would panic.
+    Writing,
 }
 
 impl<T> RefCell<T> {
@@ -265,9 +394,25 @@ impl<T> RefCell<T> {
     }
 }
 
+/// Returns whether the user has asked for backtraces via the `RUST_BACKTRACE` environment
+/// variable.
+fn backtraces_requested() -> bool {
+    env::var("RUST_BACKTRACE")
+        .map(|var| !var.is_empty())
+        .unwrap_or(false)
+}
+
 /// Print a backtrace without any frames from the backtrace library.
-fn print_filtered_backtrace(backtrace: &Backtrace) {
-    let mut idx = 1;
+fn print_filtered_backtrace(backtrace: &mut Backtrace) {
+    for (idx, repr) in filtered_backtrace_frames(backtrace).into_iter().enumerate() {
+        eprintln!("{:4}: {}", idx + 1, repr);
+    }
+}
+
+/// Resolve a backtrace and render each frame, dropping frames from the backtrace library itself.
+fn filtered_backtrace_frames(backtrace: &mut Backtrace) -> Vec<String> {
+    backtrace.resolve();
+    let mut frames = Vec::new();
     for frame in backtrace.frames().iter() {
         let symbol = frame.symbols().first();
         let repr = match symbol {
@@ -287,9 +432,9 @@ fn print_filtered_backtrace(backtrace: &Backtrace) {
                 repr
             }
         };
-        eprintln!("{:4}: {}", idx, repr);
-        idx += 1;
+        frames.push(repr);
     }
+    frames
 }
 
 impl<T: Clone> Clone for RefCell<T> {
@@ -337,10 +482,10 @@ pub fn ref_mut_filter_map<T: ?Sized, U: ?Sized, F: FnOnce(&mut T) -> Option<&mut
 
 #[cfg(test)]
 mod tests {
-    use super::{Ref, RefCell};
+    use super::{BorrowState, Ref, RefCell, RefMut};
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_borrow_mutably() {
         let c = RefCell::new(5);
         let _b = c.borrow();
@@ -348,7 +493,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already mutably borrowed")]
+    #[should_panic(expected = "is already mutably borrowed")]
     fn cannot_borrow_immutably() {
         let c = RefCell::new(5);
         let _b = c.borrow_mut();
@@ -356,7 +501,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_double_borrow_mut() {
         let c = RefCell::new(5);
         let _b = c.borrow_mut();
@@ -388,6 +533,54 @@ mod tests {
         let _b2 = c.borrow_mut();
     }
 
+    #[test]
+    fn borrow_state_reflects_outstanding_borrows() {
+        let c = RefCell::new(5);
+        assert_eq!(c.borrow_state(), BorrowState::Unused);
+        let b = c.borrow();
+        assert_eq!(c.borrow_state(), BorrowState::Reading);
+        drop(b);
+        assert_eq!(c.borrow_state(), BorrowState::Unused);
+        let b = c.borrow_mut();
+        assert_eq!(c.borrow_state(), BorrowState::Writing);
+        drop(b);
+        assert_eq!(c.borrow_state(), BorrowState::Unused);
+    }
+
+    #[test]
+    fn outstanding_borrows_reports_each_live_borrow() {
+        let c = RefCell::new(5);
+        assert!(c.outstanding_borrows().is_empty());
+        let _b = c.borrow();
+        let _b2 = Ref::clone(&_b);
+        let reports = c.outstanding_borrows();
+        assert_eq!(reports.len(), 2);
+        assert_ne!(reports[0].id, reports[1].id);
+    }
+
+    #[test]
+    fn map_split_tracks_each_half_independently() {
+        let c = RefCell::new((1, 2));
+        let (a, b) = Ref::map_split(c.borrow(), |pair| (&pair.0, &pair.1));
+        assert_eq!(*a, 1);
+        assert_eq!(*b, 2);
+        drop(a);
+        assert_eq!(c.borrow_state(), BorrowState::Reading);
+        drop(b);
+        assert_eq!(c.borrow_state(), BorrowState::Unused);
+    }
+
+    #[test]
+    fn map_split_mut_tracks_each_half_independently() {
+        let c = RefCell::new((1, 2));
+        {
+            let (mut a, mut b) = RefMut::map_split(c.borrow_mut(), |pair| (&mut pair.0, &mut pair.1));
+            *a += 10;
+            *b += 20;
+        }
+        assert_eq!(*c.borrow(), (11, 22));
+    }
+
     #[test]
     fn take_refcell_returns_correct_value() {
         let c: RefCell<i32> = RefCell::new(5);
@@ -396,7 +589,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_take_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow();
@@ -404,7 +597,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_take_mut_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow_mut();
@@ -419,7 +612,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_replace_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow();
@@ -427,7 +620,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_replace_mut_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow_mut();
@@ -442,7 +635,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_replace_with_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow();
@@ -450,7 +643,7 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "RefCell is already borrowed")]
+    #[should_panic(expected = "is already borrowed")]
     fn cannot_replace_with_mut_borrowed_refcell() {
         let c = RefCell::new(5);
         let _b = c.borrow_mut();
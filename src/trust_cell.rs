@@ -0,0 +1,339 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A `Sync` sibling of `RefCell` that uses atomic borrow counting instead of an inner
+//! `std::cell::RefCell`, modeled on shred's `TrustCell`, while still recording per-borrow
+//! backtraces for diagnostics.
+
+use std::any::type_name;
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use backtrace::Backtrace;
+
+use crate::{backtraces_requested, print_filtered_backtrace};
+
+/// Sentinel value of `AccountableTrustCell::flag` indicating a unique (mutable) borrow is held.
+/// Any other value is a count of outstanding shared (immutable) borrows, with `0` meaning unused.
+const UNIQUE_BORROW: usize = usize::MAX;
+
+/// A thread-safe `RefCell` that tracks outstanding borrows and reports stack traces for dynamic
+/// borrow failures.
+pub struct AccountableTrustCell<T: ?Sized> {
+    flag: AtomicUsize,
+    borrows: Mutex<BorrowData>,
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AccountableTrustCell<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for AccountableTrustCell<T> {}
+
+struct BorrowData {
+    next_id: usize,
+    borrows: Vec<BorrowRecord>,
+}
+
+struct BorrowRecord {
+    id: usize,
+    // `None` when `RUST_BACKTRACE` wasn't set at the time this borrow was taken.
+    backtrace: Option<Backtrace>,
+}
+
+impl BorrowData {
+    fn record(&mut self) -> usize {
+        let id = self.next_id();
+        let backtrace = if backtraces_requested() {
+            Some(Backtrace::new_unresolved())
+        } else {
+            None
+        };
+        self.borrows.push(BorrowRecord { id, backtrace });
+        id
+    }
+
+    fn next_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        id
+    }
+
+    fn remove_matching_record(&mut self, id: usize) {
+        let idx = self.borrows.iter().position(|record| record.id == id);
+        self.borrows.remove(idx.expect("missing borrow record"));
+    }
+}
+
+impl<T> AccountableTrustCell<T> {
+    /// Create a new AccountableTrustCell value.
+    pub fn new(value: T) -> AccountableTrustCell<T> {
+        AccountableTrustCell {
+            flag: AtomicUsize::new(0),
+            borrows: Mutex::new(BorrowData {
+                next_id: 0,
+                borrows: vec![],
+            }),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    /// Discard this AccountableTrustCell and return the value stored inside of it.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> AccountableTrustCell<T> {
+    /// Borrow the value stored in this cell immutably. Panics if a mutable borrow of the same
+    /// cell is currently held on any thread.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_outstanding_borrows();
+                panic!("{}", e);
+            }
+        }
+    }
+
+    /// Borrow the value stored in this cell immutably, failing rather than panicking if a
+    /// mutable borrow of the same cell is currently held on any thread.
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, InvalidBorrow> {
+        loop {
+            let current = self.flag.load(Ordering::Acquire);
+            if current == UNIQUE_BORROW {
+                return Err(InvalidBorrow::already_mutably_borrowed::<T>());
+            }
+            let next = current + 1;
+            if self
+                .flag
+                .compare_exchange_weak(current, next, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                let id = self.borrows.lock().unwrap().record();
+                return Ok(Ref {
+                    cell: self,
+                    id,
+                    value: unsafe { &*self.inner.get() },
+                });
+            }
+        }
+    }
+
+    /// Borrow the value stored in this cell mutably. Panics if there are any other outstanding
+    /// borrows of this cell, on this thread or any other (mutable borrows are unique).
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(e) => {
+                self.print_outstanding_borrows();
+                panic!("{}", e);
+            }
+        }
+    }
+
+    /// Borrow the value stored in this cell mutably, failing rather than panicking if there are
+    /// any other outstanding borrows of this cell.
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, InvalidBorrow> {
+        if self
+            .flag
+            .compare_exchange(0, UNIQUE_BORROW, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            let id = self.borrows.lock().unwrap().record();
+            Ok(RefMut {
+                cell: self,
+                id,
+                value: unsafe { &mut *self.inner.get() },
+            })
+        } else {
+            Err(InvalidBorrow::already_borrowed::<T>())
+        }
+    }
+
+    fn print_outstanding_borrows(&self) {
+        if !backtraces_requested() {
+            return;
+        }
+        eprintln!(
+            "Outstanding borrow(s) of AccountableTrustCell<{}>:",
+            type_name::<T>()
+        );
+        for borrow in &mut self.borrows.lock().unwrap().borrows {
+            if let Some(backtrace) = &mut borrow.backtrace {
+                print_filtered_backtrace(backtrace);
+            }
+            eprintln!();
+        }
+    }
+}
+
+/// An immutable reference to the value stored in an `AccountableTrustCell`.
+pub struct Ref<'a, T: ?Sized + 'a> {
+    cell: &'a AccountableTrustCell<T>,
+    id: usize,
+    value: &'a T,
+}
+
+impl<'a, T: ?Sized> Deref for Ref<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> Drop for Ref<'a, T> {
+    fn drop(&mut self) {
+        self.cell.borrows.lock().unwrap().remove_matching_record(self.id);
+        self.cell.flag.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// A mutable reference to the value stored in an `AccountableTrustCell`.
+pub struct RefMut<'a, T: ?Sized + 'a> {
+    cell: &'a AccountableTrustCell<T>,
+    id: usize,
+    value: &'a mut T,
+}
+
+impl<'a, T: ?Sized> Deref for RefMut<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized> Drop for RefMut<'a, T> {
+    fn drop(&mut self) {
+        self.cell.borrows.lock().unwrap().remove_matching_record(self.id);
+        self.cell.flag.store(0, Ordering::Release);
+    }
+}
+
+/// Error returned by the `try_borrow`/`try_borrow_mut` methods of `AccountableTrustCell` when the
+/// requested borrow is not currently available.
+#[derive(Debug)]
+pub struct InvalidBorrow {
+    message: String,
+}
+
+impl InvalidBorrow {
+    /// A `borrow_mut` conflicted with some other outstanding borrow, shared or unique.
+    fn already_borrowed<T: ?Sized>() -> InvalidBorrow {
+        InvalidBorrow {
+            message: format!(
+                "AccountableTrustCell<{}> is already borrowed.",
+                type_name::<T>()
+            ),
+        }
+    }
+
+    /// A `borrow` conflicted with an outstanding unique borrow.
+    fn already_mutably_borrowed<T: ?Sized>() -> InvalidBorrow {
+        InvalidBorrow {
+            message: format!(
+                "AccountableTrustCell<{}> is already mutably borrowed.",
+                type_name::<T>()
+            ),
+        }
+    }
+}
+
+impl fmt::Display for InvalidBorrow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for InvalidBorrow {}
+
+#[cfg(test)]
+mod tests {
+    use super::AccountableTrustCell;
+
+    #[test]
+    fn try_borrow_mut_fails_while_shared_borrow_held() {
+        let c = AccountableTrustCell::new(5);
+        let _b = c.try_borrow().unwrap();
+        assert!(c.try_borrow_mut().is_err());
+    }
+
+    #[test]
+    fn try_borrow_fails_while_unique_borrow_held() {
+        let c = AccountableTrustCell::new(5);
+        let _b = c.try_borrow_mut().unwrap();
+        assert!(c.try_borrow().is_err());
+    }
+
+    #[test]
+    fn multiple_shared_borrows_are_allowed() {
+        let c = AccountableTrustCell::new(5);
+        let _b1 = c.try_borrow().unwrap();
+        let _b2 = c.try_borrow().unwrap();
+        assert_eq!(*_b1, 5);
+        assert_eq!(*_b2, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "is already borrowed")]
+    fn cannot_double_borrow_mut() {
+        let c = AccountableTrustCell::new(5);
+        let _b = c.borrow_mut();
+        let _b2 = c.borrow_mut();
+    }
+
+    #[test]
+    #[should_panic(expected = "is already mutably borrowed")]
+    fn cannot_borrow_while_borrowed_mutably() {
+        let c = AccountableTrustCell::new(5);
+        let _b = c.borrow_mut();
+        let _b2 = c.borrow();
+    }
+
+    #[test]
+    fn mutable_borrow_observes_writes() {
+        let c = AccountableTrustCell::new(5);
+        *c.borrow_mut() = 12;
+        assert_eq!(*c.borrow(), 12);
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        // `try_borrow_mut` fails rather than blocking when another thread holds the borrow, so
+        // each increment retries until it lands, same as any other dynamically-checked borrow.
+        let c = Arc::new(AccountableTrustCell::new(0));
+        let mut handles = vec![];
+        for _ in 0..4 {
+            let c = Arc::clone(&c);
+            handles.push(thread::spawn(move || {
+                for _ in 0..100 {
+                    loop {
+                        if let Ok(mut guard) = c.try_borrow_mut() {
+                            *guard += 1;
+                            break;
+                        }
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*c.borrow(), 400);
+    }
+}